@@ -2,8 +2,8 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
-use api::{ColorF, ColorU, FontKey, FontRenderMode, FontSize, GlyphDimensions};
-use api::{FontInstanceFlags, FontVariation, NativeFontHandle};
+use api::{ColorF, ColorU, FontKey, FontRenderMode, FontSize, GlyphDimensions, IdNamespace};
+use api::{FontInstanceFlags, FontInstancePlatformOptions, FontVariation, NativeFontHandle};
 use core_foundation::base::TCFType;
 use core_foundation::dictionary::CFDictionary;
 use core_foundation::number::{CFNumber, CFNumberRef};
@@ -21,26 +21,68 @@ use core_graphics::geometry::{CGAffineTransform, CGPoint, CGSize};
 use core_graphics::geometry::{CG_AFFINE_TRANSFORM_IDENTITY, CGRect};
 use core_text;
 use core_text::font::{CTFont, CTFontRef};
-use core_text::font_descriptor::kCTFontDefaultOrientation;
+use core_text::font_descriptor::{kCTFontDefaultOrientation, kCTFontVerticalOrientation};
+use core_text::font_descriptor::CTFontOrientation;
 use core_text::font_descriptor::kCTFontURLAttribute;
+use core_text::font_descriptor::CTFontDescriptor;
 use euclid::default::Size2D;
 use crate::gamma_lut::{ColorLut, GammaLut};
 use crate::rasterizer::{FontInstance, FontTransform, GlyphKey};
 use crate::rasterizer::{GlyphFormat, GlyphRasterError, GlyphRasterResult, RasterizedGlyph};
 use crate::types::FastHashMap;
 use std::collections::hash_map::Entry;
+use std::os::raw::c_void;
 use std::sync::Arc;
 
 const INITIAL_CG_CONTEXT_SIDE_LENGTH: u32 = 32;
+// Beyond this, a cached context is considered to be pinning a pathologically large buffer and
+// is eligible for reclamation by `GraphicsContext::shrink_to_fit` regardless of recent demand.
+const DEFAULT_MAX_CG_CONTEXT_SIDE_LENGTH: u32 = 2048;
+// How many rasterizations worth of high-water data `GraphicsContext` keeps before it trusts the
+// window enough to shrink a context down to fit.
+const CG_CONTEXT_HIGH_WATER_WINDOW_LEN: usize = 64;
+
+// FreeType's default LCD filter weights, normalized so the 5 taps sum to 256.
+const LCD_FILTER_WEIGHTS: [i32; 5] = [0x08, 0x4D, 0x56, 0x4D, 0x08];
+// Number of subpixels each filter tap reaches on either side. The same count is also used as the
+// number of whole pixels of padding added on each side of the glyph, which more than covers it.
+const LCD_FILTER_RADIUS: u32 = 2;
+
+// IdNamespace reserved for FontKeys synthesized by `get_fallback_glyph`. The embedder allocates
+// its own namespaces (and, within them, font keys) starting from 0 and counting up, so minting
+// fallback keys in the *same* namespace as the primary font risks colliding with a real,
+// embedder-owned key. Using a namespace no embedder will ever hand us keeps the two key spaces
+// disjoint no matter how many fallback fonts get synthesized.
+const FALLBACK_FONT_NAMESPACE: u32 = u32::MAX;
 
 pub struct FontContext {
     cg_fonts: FastHashMap<FontKey, CGFont>,
     // Table mapping a sized font key with variations to its instantiated CoreText font.
     ct_fonts: FastHashMap<(FontKey, FontSize, Vec<FontVariation>), CTFont>,
+    // Whether a given font is known to contain color glyphs (sbix/COLR/CBDT/SVG), as reported
+    // by CoreText's symbolic traits. Populated the first time a font is added, alongside
+    // cg_fonts, so that we don't need to touch CoreText again on the hot rasterization path.
+    color_fonts: FastHashMap<FontKey, bool>,
+    // Cached CoreText cascade list (candidate substitute fonts) per primary font, so that
+    // fallback lookups don't need to re-derive it from CoreText on every miss.
+    cascade_lists: FastHashMap<FontKey, CFArray<CTFontDescriptor>>,
+    // Resolved (primary font, character) -> synthesized fallback FontKey, so that repeated
+    // get_fallback_glyph misses for the same character reuse the substitute font instead of
+    // re-walking the cascade list and minting a fresh FontKey/CGFont/CTFont every time.
+    fallback_glyphs: FastHashMap<(FontKey, char), FontKey>,
+    // Counter used to mint FontKeys (in the FALLBACK_FONT_NAMESPACE namespace) for fallback
+    // fonts resolved via get_fallback_glyph.
+    next_fallback_id: u32,
     #[allow(dead_code)]
     graphics_context: GraphicsContext,
+    // Default GammaLut used for instances that don't specify a platform gamma option.
     #[allow(dead_code)]
     gamma_lut: GammaLut,
+    // GammaLuts for instances with a non-default gamma, keyed by the bit pattern of the gamma
+    // value so that f32 can be used as a hash key. Contrast does not factor into this: it's
+    // applied later, to the preblend fraction in gamma_correct_pixels, so that it isn't double
+    // counted between the two stages.
+    gamma_luts: FastHashMap<u32, GammaLut>,
 }
 
 // core text is safe to use on multiple threads and non-shareable resources are
@@ -130,13 +172,16 @@ lazy_static! {
 
 fn get_glyph_metrics(
     ct_font: &CTFont,
+    orientation: CTFontOrientation,
     transform: Option<&CGAffineTransform>,
     glyph: CGGlyph,
     x_offset: f64,
     y_offset: f64,
     extra_width: f64,
+    stroke_radius: f64,
+    lcd_padding: i32,
 ) -> GlyphMetrics {
-    let mut bounds = ct_font.get_bounding_rects_for_glyphs(kCTFontDefaultOrientation, &[glyph]);
+    let mut bounds = ct_font.get_bounding_rects_for_glyphs(orientation, &[glyph]);
 
     if bounds.origin.x.is_nan() || bounds.origin.y.is_nan() || bounds.size.width.is_nan() ||
         bounds.size.height.is_nan()
@@ -158,14 +203,23 @@ fn get_glyph_metrics(
 
     let mut advance = CGSize { width: 0.0, height: 0.0 };
     unsafe {
-        ct_font.get_advances_for_glyphs(kCTFontDefaultOrientation, &glyph, &mut advance, 1);
+        ct_font.get_advances_for_glyphs(orientation, &glyph, &mut advance, 1);
     }
 
     if bounds.size.width > 0.0 {
         bounds.size.width += extra_width;
     }
-    if advance.width > 0.0 {
-        advance.width += extra_width;
+    // In vertical orientation the relevant advance is along the height axis (top-to-bottom
+    // flow) rather than the width axis.
+    let mut advance = if orientation == kCTFontVerticalOrientation {
+        advance.height
+    } else {
+        advance.width
+    };
+    // extra_width comes from the horizontal multistrike bold loop, so it only ever applies to
+    // the horizontal advance; don't let it leak into the vertical advance of a VERTICAL instance.
+    if orientation != kCTFontVerticalOrientation && advance > 0.0 {
+        advance += extra_width;
     }
 
     if let Some(transform) = transform {
@@ -188,6 +242,22 @@ fn get_glyph_metrics(
     right += 1;
     top += 1;
 
+    // Synthetic bold via outline stroking dilates the glyph uniformly in every direction, so
+    // widen the rasterization box on all sides by the stroke width (the stroke itself only
+    // extends half of that past the outline) to avoid clipping it.
+    if stroke_radius > 0.0 {
+        let radius = stroke_radius.ceil() as i32;
+        left -= radius;
+        bottom -= radius;
+        right += radius;
+        top += radius;
+    }
+
+    // The LCD filter is a horizontal-only convolution, so only the left/right edges need the
+    // extra margin for it to read real (zeroed) coverage from instead of clamping.
+    left -= lcd_padding;
+    right += lcd_padding;
+
     let width = right - left;
     let height = top - bottom;
 
@@ -197,7 +267,7 @@ fn get_glyph_metrics(
         rasterized_height: height,
         rasterized_ascent: top,
         rasterized_descent: -bottom,
-        advance: advance.width as f32,
+        advance: advance as f32,
     }
 }
 
@@ -211,6 +281,102 @@ extern {
 
     fn CTFontCopyVariationAxes(font: CTFontRef) -> CFArrayRef;
 
+    fn CTFontCopyDefaultCascadeListForLanguages(
+        font: CTFontRef,
+        language_pref_list: CFArrayRef,
+    ) -> CFArrayRef;
+
+    fn CTFontCreatePathForGlyph(
+        font: CTFontRef,
+        glyph: CGGlyph,
+        matrix: *const CGAffineTransform,
+    ) -> CGPathRef;
+    fn CGPathApply(
+        path: CGPathRef,
+        info: *mut c_void,
+        function: extern "C" fn(*mut c_void, *const CGPathElement),
+    );
+    fn CFRelease(cf: *const c_void);
+
+    fn CTFontGetVerticalTranslationsForGlyphs(
+        font: CTFontRef,
+        glyphs: *const CGGlyph,
+        translations: *mut CGSize,
+        count: i64,
+    );
+}
+
+#[link(name = "CoreFoundation", kind = "framework")]
+extern {
+    // The user's preferred languages, most-preferred first (e.g. ["zh-Hans", "en"]). Not
+    // exposed by the core-foundation crate. Used to steer CTFontCopyDefaultCascadeListForLanguages
+    // towards the correct regional fallback under Han unification, instead of a fixed locale.
+    fn CFLocaleCopyPreferredLanguages() -> CFArrayRef;
+}
+
+type CGPathRef = *const c_void;
+
+// Mirrors CoreGraphics' CGPathElementType (CGPath.h). Not exposed by the core-graphics crate.
+#[repr(C)]
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum CGPathElementType {
+    MoveToPoint = 0,
+    AddLineToPoint = 1,
+    AddQuadCurveToPoint = 2,
+    AddCurveToPoint = 3,
+    CloseSubpath = 4,
+}
+
+// Mirrors CoreGraphics' CGPathElement (CGPath.h).
+#[repr(C)]
+struct CGPathElement {
+    element_type: CGPathElementType,
+    points: *mut CGPoint,
+}
+
+/// A single command of a flattened glyph outline, in device space.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum GlyphOutlineCommand {
+    MoveTo(f32, f32),
+    LineTo(f32, f32),
+    QuadraticCurveTo(f32, f32, f32, f32),
+    CubicCurveTo(f32, f32, f32, f32, f32, f32),
+    ClosePath,
+}
+
+extern "C" fn collect_path_element(info: *mut c_void, element: *const CGPathElement) {
+    unsafe {
+        let commands = &mut *(info as *mut Vec<GlyphOutlineCommand>);
+        let element = &*element;
+        match element.element_type {
+            CGPathElementType::MoveToPoint => {
+                let p = *element.points;
+                commands.push(GlyphOutlineCommand::MoveTo(p.x as f32, p.y as f32));
+            }
+            CGPathElementType::AddLineToPoint => {
+                let p = *element.points;
+                commands.push(GlyphOutlineCommand::LineTo(p.x as f32, p.y as f32));
+            }
+            CGPathElementType::AddQuadCurveToPoint => {
+                let pts = std::slice::from_raw_parts(element.points, 2);
+                commands.push(GlyphOutlineCommand::QuadraticCurveTo(
+                    pts[0].x as f32, pts[0].y as f32,
+                    pts[1].x as f32, pts[1].y as f32,
+                ));
+            }
+            CGPathElementType::AddCurveToPoint => {
+                let pts = std::slice::from_raw_parts(element.points, 3);
+                commands.push(GlyphOutlineCommand::CubicCurveTo(
+                    pts[0].x as f32, pts[0].y as f32,
+                    pts[1].x as f32, pts[1].y as f32,
+                    pts[2].x as f32, pts[2].y as f32,
+                ));
+            }
+            CGPathElementType::CloseSubpath => {
+                commands.push(GlyphOutlineCommand::ClosePath);
+            }
+        }
+    }
 }
 
 fn new_ct_font_with_variations(cg_font: &CGFont, size: f64, variations: &[FontVariation]) -> CTFont {
@@ -315,6 +481,52 @@ fn is_bitmap_font(font: &FontInstance) -> bool {
     font.flags.contains(FontInstanceFlags::EMBEDDED_BITMAPS)
 }
 
+// Extracts the per-instance contrast/gamma override from `font`'s platform options, defaulting
+// to (0.0, 0.0) -- meaning "use the context-wide default" -- when none was specified.
+fn instance_contrast_and_gamma(font: &FontInstance) -> (f32, f32) {
+    match font.platform_options {
+        Some(FontInstancePlatformOptions { contrast, gamma, .. }) => (contrast, gamma),
+        None => (0.0, 0.0),
+    }
+}
+
+// Vertical CJK text needs glyph bounds/advances measured along the vertical axis rather than
+// the default horizontal one.
+fn glyph_orientation(font: &FontInstance) -> CTFontOrientation {
+    if font.flags.contains(FontInstanceFlags::VERTICAL) {
+        kCTFontVerticalOrientation
+    } else {
+        kCTFontDefaultOrientation
+    }
+}
+
+// SYNTHETIC_BOLD embolds by stroking the glyph outline rather than overprinting offset
+// strikes (MULTISTRIKE_BOLD), so it needs its own, size-proportional stroke width instead of
+// a strike count. Clamp to a minimum so hairline stems still gain visible weight at tiny sizes.
+// The returned value is used as the stroke's full line width, so the outline grows by half of it
+// on each side.
+fn synthetic_bold_stroke_radius(font: &FontInstance, size: f64, strike_scale: f64) -> f64 {
+    if !font.flags.contains(FontInstanceFlags::SYNTHETIC_BOLD) {
+        return 0.0;
+    }
+    (size / 48.0).max(0.25) * strike_scale
+}
+
+// CTFontSymbolicTraits bit indicating the font contains color glyph tables
+// (sbix, COLR/CPAL, or SVG-in-OpenType). Not exposed by the core-text crate,
+// so mirror the value from CoreText's CTFontTraits.h directly.
+const K_CT_FONT_COLOR_GLYPHS_TRAIT: u32 = 1 << 13;
+
+// Ask CoreText whether a font carries color glyph tables. This requires
+// instantiating a throwaway CTFont to query its symbolic traits, so we only
+// do this once per font and cache the result alongside cg_fonts.
+fn ct_font_has_color_glyphs(cg_font: &CGFont) -> bool {
+    objc::rc::autoreleasepool(|| {
+        let ct_font = core_text::font::new_from_CGFont(cg_font, 0.);
+        ct_font.symbolic_traits() & K_CT_FONT_COLOR_GLYPHS_TRAIT != 0
+    })
+}
+
 impl FontContext {
     pub fn distribute_across_threads() -> bool {
         true
@@ -323,16 +535,37 @@ impl FontContext {
     pub fn new() -> FontContext {
         debug!("Test for subpixel AA support: {:?}", *FONT_SMOOTHING_MODE);
 
-        // Force CG to use sRGB color space to gamma correct.
+        // Force CG to use sRGB color space to gamma correct. This is just the default used
+        // for instances that don't specify their own contrast/gamma via platform options; see
+        // gamma_lut_for_instance.
         let contrast = 0.0;
         let gamma = 0.0;
 
         FontContext {
             cg_fonts: FastHashMap::default(),
             ct_fonts: FastHashMap::default(),
+            color_fonts: FastHashMap::default(),
+            cascade_lists: FastHashMap::default(),
+            fallback_glyphs: FastHashMap::default(),
+            next_fallback_id: 0,
             graphics_context: GraphicsContext::new(),
             gamma_lut: GammaLut::new(contrast, gamma, gamma),
+            gamma_luts: FastHashMap::default(),
+        }
+    }
+
+    // Returns the GammaLut to use for `font`, honoring a per-instance gamma override from its
+    // platform options, and otherwise falling back to the context-wide default. Contrast is
+    // deliberately not threaded in here: it instead shapes the preblend fraction computed in
+    // gamma_correct_pixels, so that a single contrast value isn't applied twice.
+    fn gamma_lut_for_instance(&mut self, font: &FontInstance) -> &GammaLut {
+        let (_, gamma) = instance_contrast_and_gamma(font);
+        if gamma == 0.0 {
+            return &self.gamma_lut;
         }
+        self.gamma_luts
+            .entry(gamma.to_bits())
+            .or_insert_with(|| GammaLut::new(0.0, gamma, gamma))
     }
 
     pub fn add_raw_font(&mut self, font_key: &FontKey, bytes: Arc<Vec<u8>>, index: u32) {
@@ -341,11 +574,12 @@ impl FontContext {
         }
 
         assert_eq!(index, 0);
-        let data_provider = CGDataProvider::from_buffer(bytes); 
-        let cg_font = match CGFont::from_data_provider(data_provider) { 
+        let data_provider = CGDataProvider::from_buffer(bytes);
+        let cg_font = match CGFont::from_data_provider(data_provider) {
             Err(_) => return,
             Ok(cg_font) => cg_font,
         };
+        self.color_fonts.insert(*font_key, ct_font_has_color_glyphs(&cg_font));
         self.cg_fonts.insert(*font_key, cg_font);
     }
 
@@ -384,9 +618,14 @@ impl FontContext {
             core_text::font_descriptor::new_from_postscript_name(&cf_name)
         };
 
-        // If the NativeFontHandle includes a file path, add this to the descriptor
-        // to disambiguate cases where multiple installed fonts have the same psname.
-        if native_font_handle.path.len() > 0 {
+        // If the NativeFontHandle includes a file path, add this to the descriptor to
+        // disambiguate cases where multiple installed fonts have the same psname, and so that
+        // we can instantiate the CTFont straight from the URL below. This lets CoreText
+        // memory-map the font file and share its backing store across instances, rather than
+        // every instance keeping its own full copy of the font data resident, which matters
+        // for the common case of system and user-installed fonts referenced by path.
+        let has_path = native_font_handle.path.len() > 0;
+        if has_path {
             let cf_path = CFString::new(&native_font_handle.path);
             let url_attribute = unsafe { CFString::wrap_under_get_rule(kCTFontURLAttribute) };
             let attrs = CFDictionary::from_CFType_pairs(&[
@@ -397,14 +636,47 @@ impl FontContext {
             }
         }
 
-        self.cg_fonts
-            .insert(*font_key, CGFont::from_name(&cf_name).unwrap());
+        // For native fonts without a path, keep using the name-based CGFont lookup we always
+        // have (embedded/web fonts come in through add_raw_font instead). For fonts backed by a file on disk, instantiate via the URL-bearing descriptor
+        // instead, so CoreText can map the file rather than keeping an in-memory copy of it.
+        let cg_font = if has_path {
+            core_text::font::new_from_descriptor(&desc, 0.).copy_to_CGFont()
+        } else {
+            CGFont::from_name(&cf_name).unwrap()
+        };
+        self.color_fonts.insert(*font_key, ct_font_has_color_glyphs(&cg_font));
+        self.cg_fonts.insert(*font_key, cg_font);
     }
 
     pub fn delete_font(&mut self, font_key: &FontKey) {
         if let Some(_) = self.cg_fonts.remove(font_key) {
             self.ct_fonts.retain(|k, _| k.0 != *font_key);
+            self.color_fonts.remove(font_key);
+            self.cascade_lists.remove(font_key);
         }
+
+        // Reclaim any fallback fonts synthesized on `font_key`'s behalf. They're only ever
+        // looked up relative to the primary font (see get_fallback_glyph), so once it's gone
+        // they're unreachable and would otherwise leak in cg_fonts/ct_fonts/color_fonts.
+        let stale_fallbacks: Vec<FontKey> = self.fallback_glyphs
+            .iter()
+            .filter(|(&(primary, _), _)| primary == *font_key)
+            .map(|(_, &fallback_key)| fallback_key)
+            .collect();
+        self.fallback_glyphs.retain(|&(primary, _), _| primary != *font_key);
+        for fallback_key in stale_fallbacks {
+            self.cg_fonts.remove(&fallback_key);
+            self.ct_fonts.retain(|k, _| k.0 != fallback_key);
+            self.color_fonts.remove(&fallback_key);
+        }
+    }
+
+    // Whether this font is known to carry color glyph tables (sbix/COLR/CBDT/SVG), as detected
+    // from CoreText's symbolic traits when the font was added. Distinct from is_bitmap_font:
+    // this covers color *vector* fonts (COLR/CPAL, SVG-in-OpenType) that still have real glyph
+    // metrics and should be drawn on the vector path, just with a color-capable surface.
+    fn font_has_color_glyphs(&self, font_key: FontKey) -> bool {
+        self.color_fonts.get(&font_key).copied().unwrap_or(false)
     }
 
     pub fn delete_font_instance(&mut self, instance: &FontInstance) {
@@ -434,23 +706,147 @@ impl FontContext {
     }
 
     pub fn get_glyph_index(&mut self, font_key: FontKey, ch: char) -> Option<u32> {
-        let character = ch as u16;
-        let mut glyph = 0;
+        // Encode to UTF-16 so that characters outside the BMP (emoji, many CJK
+        // extensions, math symbols) are represented as a surrogate pair rather than
+        // being truncated by a `ch as u16` cast.
+        let mut units = [0u16; 2];
+        let units = ch.encode_utf16(&mut units);
+        let mut glyphs = [0 as CGGlyph; 2];
 
         self.get_ct_font(font_key, 16.0, &[])
             .and_then(|ct_font| {
                 unsafe {
-                    let result = ct_font.get_glyphs_for_characters(&character, &mut glyph, 1);
-
-                    if result {
-                        Some(glyph as u32)
-                    } else {
-                        None
+                    let result = ct_font.get_glyphs_for_characters(
+                        units.as_ptr(),
+                        glyphs.as_mut_ptr(),
+                        units.len() as i64,
+                    );
+
+                    if !result {
+                        return None;
                     }
                 }
+
+                // For a surrogate pair, CoreText writes the composed glyph into the
+                // slot for the leading unit and 0 into the slot for the trailing unit.
+                let glyph = glyphs[0];
+                if glyph == 0 {
+                    None
+                } else {
+                    Some(glyph as u32)
+                }
             })
     }
 
+    // Opt-in fallback lookup for when `font_key` has no glyph for `ch`. Walks the CoreText
+    // cascade list for the primary font, looking for the first substitute that does cover the
+    // character, instantiates it through the regular ct_fonts cache, and mints a FontKey for
+    // it so the rasterizer can register and draw glyphs from the substitute font instance.
+    // Resolution is cached per (font_key, ch) so that repeated misses for the same character
+    // reuse the substitute font instead of re-walking the cascade list and minting a new key.
+    pub fn get_fallback_glyph(
+        &mut self,
+        font_key: FontKey,
+        size: f64,
+        variations: &[FontVariation],
+        ch: char,
+    ) -> Option<(u32, FontKey)> {
+        if let Some(&fallback_key) = self.fallback_glyphs.get(&(font_key, ch)) {
+            return self.get_ct_font(fallback_key, size, variations).and_then(|ct_font| {
+                let mut units = [0u16; 2];
+                let units = ch.encode_utf16(&mut units);
+                let mut glyphs = [0 as CGGlyph; 2];
+                let has_glyph = unsafe {
+                    ct_font.get_glyphs_for_characters(
+                        units.as_ptr(),
+                        glyphs.as_mut_ptr(),
+                        units.len() as i64,
+                    )
+                };
+                if !has_glyph || glyphs[0] == 0 {
+                    None
+                } else {
+                    Some((glyphs[0] as u32, fallback_key))
+                }
+            });
+        }
+
+        objc::rc::autoreleasepool(|| {
+            let primary_ct_font = self.get_ct_font(font_key, size, variations)?;
+
+            let cascade_list = match self.cascade_lists.entry(font_key) {
+                Entry::Occupied(entry) => entry.get().clone(),
+                Entry::Vacant(entry) => {
+                    // Use the user's actual preferred languages, not a fixed locale: under Han
+                    // unification the language list is what steers CoreText towards the correct
+                    // regional CJK fallback, so a hardcoded "en" would pick wrong-region glyphs
+                    // for zh/ja/ko text. CoreText falls back to its own default ordering if this
+                    // list doesn't help resolve a given character.
+                    let languages: CFArray<CFString> = unsafe {
+                        let langs_ref = CFLocaleCopyPreferredLanguages();
+                        if langs_ref.is_null() {
+                            CFArray::from_CFTypes(&[CFString::new("en")])
+                        } else {
+                            TCFType::wrap_under_create_rule(langs_ref)
+                        }
+                    };
+                    let list_ref = unsafe {
+                        CTFontCopyDefaultCascadeListForLanguages(
+                            primary_ct_font.as_concrete_TypeRef(),
+                            languages.as_concrete_TypeRef(),
+                        )
+                    };
+                    if list_ref.is_null() {
+                        return None;
+                    }
+                    let list: CFArray<CTFontDescriptor> =
+                        unsafe { TCFType::wrap_under_create_rule(list_ref) };
+                    entry.insert(list.clone());
+                    list
+                }
+            };
+
+            let mut units = [0u16; 2];
+            let units = ch.encode_utf16(&mut units);
+
+            for descriptor in cascade_list.iter() {
+                if !descriptor.instance_of::<CTFontDescriptor>() {
+                    continue;
+                }
+                let candidate = core_text::font::new_from_descriptor(&descriptor, size);
+                let mut glyphs = [0 as CGGlyph; 2];
+                let has_glyph = unsafe {
+                    candidate.get_glyphs_for_characters(
+                        units.as_ptr(),
+                        glyphs.as_mut_ptr(),
+                        units.len() as i64,
+                    )
+                };
+                if !has_glyph || glyphs[0] == 0 {
+                    continue;
+                }
+
+                // Mint the key in a namespace reserved for synthesized fallbacks so it can
+                // never collide with a real, embedder-allocated key (see
+                // FALLBACK_FONT_NAMESPACE).
+                let fallback_key = FontKey::new(IdNamespace(FALLBACK_FONT_NAMESPACE), self.next_fallback_id);
+                self.next_fallback_id += 1;
+                let cg_font = candidate.copy_to_CGFont();
+                self.color_fonts.insert(fallback_key, ct_font_has_color_glyphs(&cg_font));
+                self.cg_fonts.insert(fallback_key, cg_font);
+                self.ct_fonts.insert(
+                    (fallback_key, FontSize::from_f64_px(size), variations.to_vec()),
+                    candidate,
+                );
+                self.fallback_glyphs.insert((font_key, ch), fallback_key);
+
+                return Some((glyphs[0] as u32, fallback_key));
+            }
+
+            None
+        })
+    }
+
     pub fn get_glyph_dimensions(
         &mut self,
         font: &FontInstance,
@@ -461,11 +857,22 @@ impl FontContext {
         self.get_ct_font(font.font_key, size, &font.variations)
             .and_then(|ct_font| {
                 let glyph = key.index() as CGGlyph;
-                let bitmap = is_bitmap_font(font);
-                let (mut shape, (x_offset, y_offset)) = if bitmap {
-                    (FontTransform::identity(), (0.0, 0.0))
+                // Mirror rasterize_glyph's glyph_type classification so the dimensions reported
+                // here (and thus the buffer rasterize_glyph is asked to fill) agree with what it
+                // actually draws: a COLR/SVG color-vector glyph is not a bitmap, but it still
+                // forgoes the LCD/smoothing treatment below just like Bitmap does.
+                let glyph_type = if is_bitmap_font(font) {
+                    GlyphType::Bitmap
+                } else if self.font_has_color_glyphs(font.font_key) {
+                    GlyphType::ColorVector
                 } else {
-                    (font.transform.invert_scale(y_scale, y_scale), font.get_subpx_offset(key))
+                    GlyphType::Vector
+                };
+                let (mut shape, (x_offset, y_offset)) = match glyph_type {
+                    GlyphType::Bitmap => (FontTransform::identity(), (0.0, 0.0)),
+                    GlyphType::Vector | GlyphType::ColorVector => {
+                        (font.transform.invert_scale(y_scale, y_scale), font.get_subpx_offset(key))
+                    }
                 };
                 if font.flags.contains(FontInstanceFlags::FLIP_X) {
                     shape = shape.flip_x();
@@ -495,22 +902,34 @@ impl FontContext {
                 } else {
                     None
                 };
-                let (strike_scale, pixel_step) = if bitmap {
+                let (strike_scale, pixel_step) = if glyph_type == GlyphType::Bitmap {
                     (y_scale, 1.0)
                 } else {
                     (x_scale, y_scale / x_scale)
                 };
-                let extra_strikes = font.get_extra_strikes(
-                    FontInstanceFlags::SYNTHETIC_BOLD | FontInstanceFlags::MULTISTRIKE_BOLD,
-                    strike_scale,
+                // Synthetic bold via stroking replaces the multistrike loop entirely.
+                let extra_strikes = if font.flags.contains(FontInstanceFlags::SYNTHETIC_BOLD) {
+                    0
+                } else {
+                    font.get_extra_strikes(FontInstanceFlags::MULTISTRIKE_BOLD, strike_scale)
+                };
+                let stroke_radius = synthetic_bold_stroke_radius(font, size, strike_scale);
+                let use_font_smoothing = font.flags.contains(FontInstanceFlags::FONT_SMOOTHING);
+                let smooth = glyph_type == GlyphType::Vector && matches!(
+                    (font.render_mode, use_font_smoothing),
+                    (FontRenderMode::Subpixel, _) | (FontRenderMode::Alpha, true)
                 );
+                let lcd_padding = if smooth { LCD_FILTER_RADIUS as i32 } else { 0 };
                 let metrics = get_glyph_metrics(
                     &ct_font,
+                    glyph_orientation(font),
                     transform.as_ref(),
                     glyph,
                     x_offset,
                     y_offset,
                     extra_strikes as f64 * pixel_step,
+                    stroke_radius,
+                    lcd_padding,
                 );
                 if metrics.rasterized_width == 0 || metrics.rasterized_height == 0 {
                     None
@@ -526,34 +945,161 @@ impl FontContext {
             })
     }
 
+    /// Returns the vector outline of a glyph as a flattened list of path commands in device
+    /// space, or `None` if the glyph has no path (e.g. whitespace, or a bitmap-only glyph).
+    /// This is used for path-based rendering, hit-testing, and SVG/blob export, none of which
+    /// can be satisfied by the bounding-rect metrics `get_glyph_dimensions` provides.
+    pub fn get_glyph_outline(
+        &mut self,
+        font: &FontInstance,
+        key: &GlyphKey,
+    ) -> Option<Vec<GlyphOutlineCommand>> {
+        let (_, y_scale) = font.transform.compute_scale().unwrap_or((1.0, 1.0));
+        let size = font.size.to_f64_px() * y_scale;
+        self.get_ct_font(font.font_key, size, &font.variations)
+            .and_then(|ct_font| {
+                // Mirrors the transform construction in get_glyph_dimensions/rasterize_glyph so
+                // that the outline lines up with the rasterized glyph in device space.
+                let mut shape = font.transform.invert_scale(y_scale, y_scale);
+                if font.flags.contains(FontInstanceFlags::FLIP_X) {
+                    shape = shape.flip_x();
+                }
+                if font.flags.contains(FontInstanceFlags::FLIP_Y) {
+                    shape = shape.flip_y();
+                }
+                if font.flags.contains(FontInstanceFlags::TRANSPOSE) {
+                    shape = shape.swap_xy();
+                }
+                let (mut tx, mut ty) = (0.0, 0.0);
+                if font.synthetic_italics.is_enabled() {
+                    let (shape_, (tx_, ty_)) = font.synthesize_italics(shape, size);
+                    shape = shape_;
+                    tx = tx_;
+                    ty = ty_;
+                }
+                let matrix = CGAffineTransform {
+                    a: shape.scale_x as f64,
+                    b: -shape.skew_y as f64,
+                    c: -shape.skew_x as f64,
+                    d: shape.scale_y as f64,
+                    tx,
+                    ty: -ty,
+                };
+
+                let glyph = key.index() as CGGlyph;
+                let path_ref = unsafe {
+                    CTFontCreatePathForGlyph(ct_font.as_concrete_TypeRef(), glyph, &matrix)
+                };
+                if path_ref.is_null() {
+                    return None;
+                }
+
+                let mut commands = Vec::new();
+                unsafe {
+                    CGPathApply(
+                        path_ref,
+                        &mut commands as *mut Vec<GlyphOutlineCommand> as *mut c_void,
+                        collect_path_element,
+                    );
+                    CFRelease(path_ref);
+                }
+
+                if commands.is_empty() {
+                    None
+                } else {
+                    Some(commands)
+                }
+            })
+    }
+
     // Assumes the pixels here are linear values from CG
     fn gamma_correct_pixels(
-        &self,
+        gamma_lut: &GammaLut,
         pixels: &mut Vec<u8>,
         render_mode: FontRenderMode,
         color: ColorU,
+        contrast: f32,
     ) {
         let ColorU {r, g, b, a} = color;
-        let smooth_color = match *FONT_SMOOTHING_MODE {
-            // Use Skia's gamma approximation for subpixel smoothing of 3/4.
-            Some(FontRenderMode::Subpixel) => ColorU::new(r - r / 4, g - g / 4, b - b / 4, a),
-            // Use Skia's gamma approximation for grayscale smoothing of 1/2.
-            Some(FontRenderMode::Alpha) => ColorU::new(r / 2, g / 2, b / 2, a),
-            _ => color,
+        // Skia's gamma approximation for the preblend: subpixel smoothing keeps 3/4 of the text
+        // color, grayscale smoothing keeps 1/2. `contrast` (the per-instance platform option;
+        // it does not shape `gamma_lut`, see gamma_lut_for_instance) nudges this fraction towards
+        // 1.0 (more of the text color kept, so text reads heavier/crisper) or down towards 0.0
+        // (lighter), instead of being locked to the fixed Skia constants. This only runs for
+        // Subpixel and Alpha + smoothing glyphs; Alpha without smoothing and Mono glyphs are not
+        // affected by contrast.
+        let base_fraction = match *FONT_SMOOTHING_MODE {
+            Some(FontRenderMode::Subpixel) => 0.75,
+            Some(FontRenderMode::Alpha) => 0.5,
+            _ => 1.0,
+        };
+        let fraction = (base_fraction + contrast * (1.0 - base_fraction)).max(0.0).min(1.0);
+        let smooth_color = if fraction < 1.0 {
+            ColorU::new(
+                (r as f32 * fraction) as u8,
+                (g as f32 * fraction) as u8,
+                (b as f32 * fraction) as u8,
+                a,
+            )
+        } else {
+            color
         };
 
         // Then convert back to gamma corrected values.
         match render_mode {
             FontRenderMode::Alpha => {
-                self.gamma_lut.preblend_grayscale(pixels, smooth_color);
+                gamma_lut.preblend_grayscale(pixels, smooth_color);
             }
             FontRenderMode::Subpixel => {
-                self.gamma_lut.preblend(pixels, smooth_color);
+                gamma_lut.preblend(pixels, smooth_color);
             }
             _ => {} // Again, give mono untouched since only the alpha matters.
         }
     }
 
+    // FreeType-style horizontal FIR filter applied to the linear-space RGB coverage mask before
+    // gamma correction, to spread out the saturated color fringes that a naive per-channel
+    // subpixel rasterization leaves on stem edges. The filter treats a row's R/G/B bytes as one
+    // contiguous stream of subpixels -- as they physically are on an LCD triad -- so each output
+    // subpixel mixes its 5 *neighboring subpixels across channel boundaries*, which is what
+    // actually redistributes energy and cancels the fringe; convolving each channel only against
+    // its own plane (same channel of neighboring whole pixels) would just blur the glyph.
+    // The caller is expected to have widened the rasterized rect by LCD_FILTER_RADIUS pixels on
+    // both sides (see get_glyph_metrics's lcd_padding), so reads past the glyph's own bounds land
+    // on real, zeroed coverage rather than needing special-cased clamping here.
+    fn apply_lcd_filter(pixels: &mut [u8], width: usize, height: usize) {
+        // This surface is BGRA in memory (see print_glyph_data); the alpha byte carries no
+        // subpixel coverage, so only the 3 color bytes of each pixel join the subpixel stream.
+        const CHANNELS: usize = 3;
+        let stride = width * 4;
+        let mut subpixels = vec![0u8; width * CHANNELS];
+        let mut filtered = vec![0u8; width * CHANNELS];
+        for y in 0 .. height {
+            let row = &pixels[y * stride .. y * stride + stride];
+            for x in 0 .. width {
+                subpixels[x * CHANNELS .. x * CHANNELS + CHANNELS]
+                    .copy_from_slice(&row[x * 4 .. x * 4 + CHANNELS]);
+            }
+
+            let len = subpixels.len();
+            for i in 0 .. len {
+                let mut sum = 0i32;
+                for (tap, &weight) in LCD_FILTER_WEIGHTS.iter().enumerate() {
+                    let sample = i as isize + tap as isize - LCD_FILTER_RADIUS as isize;
+                    if sample >= 0 && (sample as usize) < len {
+                        sum += weight * subpixels[sample as usize] as i32;
+                    }
+                }
+                filtered[i] = (sum >> 8) as u8;
+            }
+
+            for x in 0 .. width {
+                pixels[y * stride + x * 4 .. y * stride + x * 4 + CHANNELS]
+                    .copy_from_slice(&filtered[x * CHANNELS .. x * CHANNELS + CHANNELS]);
+            }
+        }
+    }
+
     #[allow(dead_code)]
     fn print_glyph_data(&mut self, data: &[u8], width: usize, height: usize) {
         // Rust doesn't have step_by support on stable :(
@@ -621,6 +1167,22 @@ impl FontContext {
     pub fn end_rasterize(_font: &FontInstance) {
     }
 
+    // Bounds the cached Core Graphics context sizes that `shrink_to_fit` is willing to keep
+    // around without recent demand justifying them. Not required for correctness; callers that
+    // don't care can leave this at its default.
+    #[allow(dead_code)]
+    pub fn set_max_context_side_length(&mut self, max_side_length: u32) {
+        self.graphics_context.set_max_context_side_length(max_side_length);
+    }
+
+    // Reclaims memory from cached rasterization contexts that grew to fit a large glyph but
+    // haven't needed that room lately. Meant to be called between frames rather than per-glyph,
+    // since it walks a window of recent high-water data and is therefore wasted work if called
+    // more often than rasterization patterns actually change.
+    pub fn shrink_to_fit(&mut self) {
+        self.graphics_context.shrink_to_fit();
+    }
+
     pub fn rasterize_glyph(&mut self, font: &FontInstance, key: &GlyphKey) -> GlyphRasterResult {
         objc::rc::autoreleasepool(|| {
         let (x_scale, y_scale) = font.transform.compute_scale().unwrap_or((1.0, 1.0));
@@ -629,13 +1191,15 @@ impl FontContext {
             self.get_ct_font(font.font_key, size, &font.variations).ok_or(GlyphRasterError::LoadFailed)?;
         let glyph_type = if is_bitmap_font(font) {
             GlyphType::Bitmap
+        } else if self.font_has_color_glyphs(font.font_key) {
+            GlyphType::ColorVector
         } else {
             GlyphType::Vector
         };
 
         let (mut shape, (x_offset, y_offset)) = match glyph_type {
             GlyphType::Bitmap => (FontTransform::identity(), (0.0, 0.0)),
-            GlyphType::Vector => {
+            GlyphType::Vector | GlyphType::ColorVector => {
                 (font.transform.invert_scale(y_scale, y_scale), font.get_subpx_offset(key))
             }
         };
@@ -674,26 +1238,13 @@ impl FontContext {
         } else {
             (x_scale, y_scale / x_scale)
         };
-        let extra_strikes = font.get_extra_strikes(
-            FontInstanceFlags::SYNTHETIC_BOLD | FontInstanceFlags::MULTISTRIKE_BOLD,
-            strike_scale,
-        );
-        let metrics = get_glyph_metrics(
-            &ct_font,
-            transform.as_ref(),
-            glyph,
-            x_offset,
-            y_offset,
-            extra_strikes as f64 * pixel_step,
-        );
-        if metrics.rasterized_width == 0 || metrics.rasterized_height == 0 {
-            return Err(GlyphRasterError::LoadFailed);
-        }
-
-        let raster_size = Size2D::new(
-            metrics.rasterized_width as u32,
-            metrics.rasterized_height as u32
-        );
+        // Synthetic bold via stroking (below) replaces the multistrike loop entirely.
+        let extra_strikes = if font.flags.contains(FontInstanceFlags::SYNTHETIC_BOLD) {
+            0
+        } else {
+            font.get_extra_strikes(FontInstanceFlags::MULTISTRIKE_BOLD, strike_scale)
+        };
+        let stroke_radius = synthetic_bold_stroke_radius(font, size, strike_scale);
 
         // If the font render mode is Alpha, we support two different ways to
         // compute the grayscale mask, depending on the value of the platform
@@ -725,7 +1276,9 @@ impl FontContext {
         // in case it is necessary.
         let use_font_smoothing = font.flags.contains(FontInstanceFlags::FONT_SMOOTHING);
         let (antialias, smooth, text_color, bg_color) = match glyph_type {
-            GlyphType::Bitmap => (true, false, ColorF::from(font.color), ColorF::TRANSPARENT),
+            GlyphType::Bitmap | GlyphType::ColorVector => {
+                (true, false, ColorF::from(font.color), ColorF::TRANSPARENT)
+            }
             GlyphType::Vector => {
                 match (font.render_mode, use_font_smoothing) {
                     (FontRenderMode::Subpixel, _) |
@@ -736,6 +1289,31 @@ impl FontContext {
             }
         };
 
+        // `smooth` is only set for Subpixel and "Alpha + smoothing" glyphs, which are exactly
+        // the cases where we apply the LCD filter below to tame color fringing. The filter's
+        // 5-tap kernel reaches 2 subpixels past each edge, so pad the rasterization rect here to
+        // give it real (zeroed) coverage to read instead of clamping at the glyph's own edges.
+        let lcd_padding = if smooth { LCD_FILTER_RADIUS as i32 } else { 0 };
+        let metrics = get_glyph_metrics(
+            &ct_font,
+            glyph_orientation(font),
+            transform.as_ref(),
+            glyph,
+            x_offset,
+            y_offset,
+            extra_strikes as f64 * pixel_step,
+            stroke_radius,
+            lcd_padding,
+        );
+        if metrics.rasterized_width == 0 || metrics.rasterized_height == 0 {
+            return Err(GlyphRasterError::LoadFailed);
+        }
+
+        let raster_size = Size2D::new(
+            metrics.rasterized_width as u32,
+            metrics.rasterized_height as u32
+        );
+
         {
             let cg_context = self.graphics_context.get_context(&raster_size, glyph_type);
 
@@ -779,7 +1357,21 @@ impl FontContext {
                 text_color.b.into(),
                 1.0,
             );
-            cg_context.set_text_drawing_mode(CGTextDrawingMode::CGTextFill);
+            if stroke_radius > 0.0 {
+                // True synthetic bold: stroke the outline with the fill color so the glyph
+                // grows uniformly in all directions in a single draw call, rather than the
+                // lumpy, direction-dependent weight gain of re-drawing offset strikes.
+                cg_context.set_rgb_stroke_color(
+                    text_color.r.into(),
+                    text_color.g.into(),
+                    text_color.b.into(),
+                    1.0,
+                );
+                cg_context.set_line_width(stroke_radius);
+                cg_context.set_text_drawing_mode(CGTextDrawingMode::CGTextFillStroke);
+            } else {
+                cg_context.set_text_drawing_mode(CGTextDrawingMode::CGTextFill);
+            }
 
             // CG Origin is bottom left, WR is top left. Need -y offset
             let mut draw_origin = CGPoint {
@@ -787,6 +1379,23 @@ impl FontContext {
                 y: metrics.rasterized_descent as f64 - y_offset - ty,
             };
 
+            if font.flags.contains(FontInstanceFlags::VERTICAL) {
+                // CTFontDrawGlyphs always draws using horizontal glyph origins, so shift the
+                // pen by the glyph's vertical translation to land it where top-to-bottom flow
+                // expects it.
+                let mut translation = CGSize { width: 0.0, height: 0.0 };
+                unsafe {
+                    CTFontGetVerticalTranslationsForGlyphs(
+                        ct_font.as_concrete_TypeRef(),
+                        &glyph,
+                        &mut translation,
+                        1,
+                    );
+                }
+                draw_origin.x -= translation.width;
+                draw_origin.y -= translation.height;
+            }
+
             if let Some(transform) = transform {
                 cg_context.set_text_matrix(&transform);
 
@@ -828,7 +1437,7 @@ impl FontContext {
                 // We explicitly do not do this for grayscale AA ("Alpha without
                 // smoothing" or Mono) because those rendering modes are not
                 // gamma-aware in CoreGraphics.
-                self.gamma_lut.coregraphics_convert_to_linear(
+                self.gamma_lut_for_instance(font).coregraphics_convert_to_linear(
                     &mut rasterized_pixels,
                 );
             }
@@ -850,14 +1459,30 @@ impl FontContext {
             }
 
             if smooth {
+                // Smear the per-channel coverage horizontally to kill the saturated color
+                // fringes a naive subpixel mask leaves on stem edges, then refresh alpha from
+                // the now-filtered green channel.
+                FontContext::apply_lcd_filter(
+                    &mut rasterized_pixels,
+                    raster_size.width as usize,
+                    raster_size.height as usize,
+                );
+                for pixel in rasterized_pixels.chunks_mut(4) {
+                    pixel[3] = pixel[1];
+                }
+
                 // Convert back from linear space into device space, and perform
                 // some "preblending" based on the text color.
                 // In Alpha + smoothing mode, this will also convert subpixel AA
                 // into grayscale AA.
-                self.gamma_correct_pixels(
+                let (contrast, _) = instance_contrast_and_gamma(font);
+                let gamma_lut = self.gamma_lut_for_instance(font);
+                FontContext::gamma_correct_pixels(
+                    gamma_lut,
                     &mut rasterized_pixels,
                     font.render_mode,
                     font.color,
+                    contrast,
                 );
             }
         }
@@ -868,11 +1493,14 @@ impl FontContext {
             width: metrics.rasterized_width,
             height: metrics.rasterized_height,
             scale: match glyph_type {
+                // A real embedded bitmap is pre-rendered at a fixed pixel size and needs to be
+                // scaled back to the requested size. Color vector glyphs have real outlines
+                // rasterized at the target size already, so no rescaling is needed.
                 GlyphType::Bitmap => y_scale.recip() as f32,
-                GlyphType::Vector => 1.0,
+                GlyphType::Vector | GlyphType::ColorVector => 1.0,
             },
             format: match glyph_type {
-                GlyphType::Bitmap => GlyphFormat::ColorBitmap,
+                GlyphType::Bitmap | GlyphType::ColorVector => GlyphFormat::ColorBitmap,
                 GlyphType::Vector => font.get_glyph_format(),
             },
             bytes: rasterized_pixels,
@@ -880,13 +1508,50 @@ impl FontContext {
     }
 }
 
+// Tracks the largest side length requested of a `GraphicsContext` slot over the last
+// `CG_CONTEXT_HIGH_WATER_WINDOW_LEN` rasterizations, so `shrink_to_fit` can tell whether a big
+// cached buffer is still earning its keep or is just left over from a one-off large glyph.
+struct HighWaterWindow {
+    samples: [u32; CG_CONTEXT_HIGH_WATER_WINDOW_LEN],
+    next: usize,
+    len: usize,
+}
+
+impl HighWaterWindow {
+    fn new() -> Self {
+        HighWaterWindow { samples: [0; CG_CONTEXT_HIGH_WATER_WINDOW_LEN], next: 0, len: 0 }
+    }
+
+    fn record(&mut self, side_length: u32) {
+        self.samples[self.next] = side_length;
+        self.next = (self.next + 1) % self.samples.len();
+        self.len = usize::min(self.len + 1, self.samples.len());
+    }
+
+    fn is_full(&self) -> bool {
+        self.len == self.samples.len()
+    }
+
+    fn max(&self) -> u32 {
+        self.samples[.. self.len].iter().cloned().max().unwrap_or(0)
+    }
+
+    fn clear(&mut self) {
+        self.len = 0;
+        self.next = 0;
+    }
+}
+
 // Avoids taking locks by recycling Core Graphics contexts.
 #[allow(dead_code)]
 struct GraphicsContext {
     vector_context: CGContext,
     vector_context_size: Size2D<u32>,
+    vector_high_water: HighWaterWindow,
     bitmap_context: CGContext,
     bitmap_context_size: Size2D<u32>,
+    bitmap_high_water: HighWaterWindow,
+    max_context_side_length: u32,
 }
 
 impl GraphicsContext {
@@ -895,24 +1560,32 @@ impl GraphicsContext {
         GraphicsContext {
             vector_context: GraphicsContext::create_cg_context(&size, GlyphType::Vector),
             vector_context_size: size,
+            vector_high_water: HighWaterWindow::new(),
             bitmap_context: GraphicsContext::create_cg_context(&size, GlyphType::Bitmap),
             bitmap_context_size: size,
+            bitmap_high_water: HighWaterWindow::new(),
+            max_context_side_length: DEFAULT_MAX_CG_CONTEXT_SIDE_LENGTH,
         }
     }
 
+    // Bounds how large a context `shrink_to_fit` will consider "still earning its keep". A
+    // context above this is eligible for reclamation even if recent glyphs have kept it busy.
+    #[allow(dead_code)]
+    fn set_max_context_side_length(&mut self, max_side_length: u32) {
+        self.max_context_side_length = max_side_length;
+    }
+
     #[allow(dead_code)]
     fn get_context(&mut self, size: &Size2D<u32>, glyph_type: GlyphType)
                    -> &mut CGContext {
-        let (cached_context, cached_size) = match glyph_type {
-            GlyphType::Vector => {
-                (&mut self.vector_context, &mut self.vector_context_size)
-            }
-            GlyphType::Bitmap => {
-                (&mut self.bitmap_context, &mut self.bitmap_context_size)
-            }
+        let (cached_context, cached_size, high_water) = if glyph_type.is_color() {
+            (&mut self.bitmap_context, &mut self.bitmap_context_size, &mut self.bitmap_high_water)
+        } else {
+            (&mut self.vector_context, &mut self.vector_context_size, &mut self.vector_high_water)
         };
         let rounded_size = Size2D::new(size.width.next_power_of_two(),
                                        size.height.next_power_of_two());
+        high_water.record(u32::max(rounded_size.width, rounded_size.height));
         if rounded_size.width > cached_size.width || rounded_size.height > cached_size.height {
             *cached_size = Size2D::new(u32::max(cached_size.width, rounded_size.width),
                                        u32::max(cached_size.height, rounded_size.height));
@@ -921,12 +1594,66 @@ impl GraphicsContext {
         cached_context
     }
 
+    // Called between frames to reclaim memory from a context that grew to accommodate a large
+    // glyph but hasn't needed that much room recently. Cheap no-op in the common case where the
+    // high-water window isn't full yet or recent demand still justifies the cached size.
+    #[allow(dead_code)]
+    fn shrink_to_fit(&mut self) {
+        let max_side_length = self.max_context_side_length;
+        GraphicsContext::shrink_context(
+            &mut self.vector_context,
+            &mut self.vector_context_size,
+            &mut self.vector_high_water,
+            GlyphType::Vector,
+            max_side_length,
+        );
+        GraphicsContext::shrink_context(
+            &mut self.bitmap_context,
+            &mut self.bitmap_context_size,
+            &mut self.bitmap_high_water,
+            GlyphType::Bitmap,
+            max_side_length,
+        );
+    }
+
+    fn shrink_context(
+        context: &mut CGContext,
+        cached_size: &mut Size2D<u32>,
+        high_water: &mut HighWaterWindow,
+        glyph_type: GlyphType,
+        max_side_length: u32,
+    ) {
+        if !high_water.is_full() {
+            return;
+        }
+        let current_side_length = u32::max(cached_size.width, cached_size.height);
+        let recent_high_water = high_water.max();
+        // Shrink if we've grown past the configured ceiling regardless of recent demand, or if
+        // recent requests have stayed comfortably (at most half) below what's cached.
+        let should_shrink = current_side_length > max_side_length ||
+            recent_high_water.saturating_mul(2) <= current_side_length;
+        if should_shrink {
+            let target_side_length = u32::max(INITIAL_CG_CONTEXT_SIDE_LENGTH, recent_high_water)
+                .next_power_of_two()
+                .min(u32::max(max_side_length, INITIAL_CG_CONTEXT_SIDE_LENGTH));
+            if target_side_length < current_side_length {
+                let new_size = Size2D::new(target_side_length, target_side_length);
+                *context = GraphicsContext::create_cg_context(&new_size, glyph_type);
+                *cached_size = new_size;
+            }
+        }
+        // Re-arm the window regardless, so a shrink we decided against isn't retried on every
+        // single subsequent glyph until the oldest sample ages out.
+        high_water.clear();
+    }
+
     #[allow(dead_code)]
     fn get_rasterized_pixels(&mut self, size: &Size2D<u32>, glyph_type: GlyphType)
                              -> Vec<u8> {
-        let (cached_context, cached_size) = match glyph_type {
-            GlyphType::Vector => (&mut self.vector_context, &self.vector_context_size),
-            GlyphType::Bitmap => (&mut self.bitmap_context, &self.bitmap_context_size),
+        let (cached_context, cached_size) = if glyph_type.is_color() {
+            (&mut self.bitmap_context, &self.bitmap_context_size)
+        } else {
+            (&mut self.vector_context, &self.vector_context_size)
         };
         let cached_data = cached_context.data();
         let cached_stride = cached_size.width as usize * 4;
@@ -965,9 +1692,10 @@ impl GraphicsContext {
         // subpixel AA at all (which we need it to do in both Subpixel and
         // Alpha+smoothing mode). But little-endian is what we want anyway, so
         // this works out nicely.
-        let color_type = match glyph_type {
-            GlyphType::Vector => kCGImageAlphaNoneSkipFirst,
-            GlyphType::Bitmap => kCGImageAlphaPremultipliedFirst,
+        let color_type = if glyph_type.is_color() {
+            kCGImageAlphaPremultipliedFirst
+        } else {
+            kCGImageAlphaNoneSkipFirst
         };
 
         CGContext::create_bitmap_context(None,
@@ -984,5 +1712,17 @@ impl GraphicsContext {
 enum GlyphType {
     Vector,
     Bitmap,
+    // A vector glyph from a color-capable font (COLR/CPAL, SVG-in-OpenType). Drawn through
+    // the same premultiplied-RGBA surface as Bitmap so per-glyph palette/layer color survives,
+    // but keeps Vector's real glyph metrics and scale since it isn't a pre-rendered bitmap.
+    ColorVector,
+}
+
+impl GlyphType {
+    // Bitmap and ColorVector both need a premultiplied-alpha surface so glyph color survives;
+    // only Vector is drawn as a white-on-black alpha mask for later mask-based compositing.
+    fn is_color(self) -> bool {
+        self != GlyphType::Vector
+    }
 }
 